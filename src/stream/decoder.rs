@@ -0,0 +1,408 @@
+use libc::c_void;
+
+use parse_code;
+use std::io::{self, Write};
+use zstd_sys;
+
+struct DecoderContext {
+    s: *mut zstd_sys::ZSTD_DStream,
+}
+
+impl Default for DecoderContext {
+    fn default() -> Self {
+        DecoderContext { s: unsafe { zstd_sys::ZSTD_createDStream() } }
+    }
+}
+
+impl Drop for DecoderContext {
+    fn drop(&mut self) {
+        let code = unsafe { zstd_sys::ZSTD_freeDStream(self.s) };
+        parse_code(code).unwrap();
+    }
+}
+
+#[derive(PartialEq)]
+enum DecoderState {
+    Accepting,
+    // Finished just makes sure writes are no longer accepted.
+    Finished,
+}
+
+/// A decoder that decompresses and forwards data to another writer.
+///
+/// This is the dual of `Encoder<W>`: bytes written to it are decompressed
+/// and forwarded to the wrapped writer as they arrive.
+///
+/// A single write can span multiple concatenated frames: once one frame is
+/// fully decoded, the stream is silently re-initialized to decode the next
+/// one, so the whole input is treated as one continuous stream.
+///
+/// Don't forget to call `finish()` before dropping it!
+pub struct Decoder<W: Write> {
+    // output writer (decompressed data)
+    writer: W,
+    // output buffer
+    buffer: Vec<u8>,
+    // offset in the output buffer
+    offset: usize,
+
+    // decompression context
+    context: DecoderContext,
+    state: DecoderState,
+    // Whether the last frame we touched was fully decoded. Used to detect
+    // a stream that ends in the middle of a frame.
+    frame_complete: bool,
+    // Kept around so each concatenated frame can be re-initialized with
+    // the same dictionary as the first one.
+    dictionary: Vec<u8>,
+}
+
+/// A wrapper around a `Decoder<W>` that finishes the stream on drop.
+pub struct AutoFinishDecoder<W: Write> {
+    // We wrap this in an option to take it during drop.
+    decoder: Option<Decoder<W>>,
+    // TODO: make this a FnOnce once it works in a Box
+    on_finish: Option<Box<FnMut(io::Result<W>)>>,
+}
+
+impl<W: Write> AutoFinishDecoder<W> {
+    fn new<F: 'static + FnMut(io::Result<W>)>(decoder: Decoder<W>,
+                                              on_finish: F)
+                                              -> Self {
+        AutoFinishDecoder {
+            decoder: Some(decoder),
+            on_finish: Some(Box::new(on_finish)),
+        }
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.decoder
+            .as_ref()
+            .unwrap()
+            .get_ref()
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutation of the writer may result in surprising results if
+    /// this decoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.decoder
+            .as_mut()
+            .unwrap()
+            .get_mut()
+    }
+}
+
+impl<W: Write> Drop for AutoFinishDecoder<W> {
+    fn drop(&mut self) {
+        let result = self.decoder
+            .take()
+            .unwrap()
+            .finish();
+        if let Some(mut on_finish) = self.on_finish.take() {
+            on_finish(result);
+        }
+    }
+}
+
+impl<W: Write> Write for AutoFinishDecoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.decoder
+            .as_mut()
+            .unwrap()
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.decoder
+            .as_mut()
+            .unwrap()
+            .flush()
+    }
+}
+
+impl<W: Write> Decoder<W> {
+    /// Creates a new decoder.
+    pub fn new(writer: W) -> io::Result<Self> {
+        Self::with_dictionary(writer, &[])
+    }
+
+    /// Creates a new decoder, using an existing dictionary.
+    ///
+    /// (The dictionary must be the same as the one used for compression.)
+    pub fn with_dictionary(writer: W, dictionary: &[u8]) -> io::Result<Self> {
+        let context = DecoderContext::default();
+
+        parse_code(unsafe {
+            zstd_sys::ZSTD_initDStream_usingDict(context.s,
+                                           dictionary.as_ptr() as *const c_void,
+                                           dictionary.len())
+        })?;
+
+        Decoder::with_context(writer, context, dictionary.to_vec())
+    }
+
+    /// Returns a wrapper around `self` that will finish the stream on drop.
+    ///
+    /// # Panic
+    ///
+    /// Panics on drop if an error happens when finishing the stream.
+    pub fn auto_finish(self) -> AutoFinishDecoder<W> {
+        self.on_finish(|result| { result.unwrap(); })
+    }
+
+    /// Returns a decoder that will finish the stream on drop.
+    ///
+    /// Calls the given callback with the result from `finish()`.
+    pub fn on_finish<F: 'static + FnMut(io::Result<W>)>
+        (self, f: F)
+         -> AutoFinishDecoder<W> {
+        AutoFinishDecoder::new(self, f)
+    }
+
+    fn with_context(writer: W, context: DecoderContext, dictionary: Vec<u8>)
+                    -> io::Result<Self> {
+        // This is the output buffer size,
+        // for decompressed data we get from zstd.
+        let buffer_size = unsafe { zstd_sys::ZSTD_DStreamOutSize() };
+
+        Ok(Decoder {
+               writer: writer,
+               buffer: Vec::with_capacity(buffer_size),
+               offset: 0,
+               context: context,
+               state: DecoderState::Accepting,
+               frame_complete: true,
+               dictionary: dictionary,
+           })
+    }
+
+    /// Re-initializes the stream to decode the next of several concatenated
+    /// frames, carrying over the dictionary the decoder was created with.
+    fn reinit_stream(&self) -> io::Result<()> {
+        parse_code(unsafe {
+            if self.dictionary.is_empty() {
+                zstd_sys::ZSTD_initDStream(self.context.s)
+            } else {
+                zstd_sys::ZSTD_initDStream_usingDict(self.context.s,
+                                               self.dictionary.as_ptr() as
+                                               *const c_void,
+                                               self.dictionary.len())
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutation of the writer may result in surprising results if
+    /// this decoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Finishes the stream. You *need* to call this after writing your stuff.
+    ///
+    /// This returns the inner writer in case you need it.
+    ///
+    /// This errors if the stream ended in the middle of a frame: a
+    /// well-formed input always leaves the decoder back in the "waiting for
+    /// a new frame" state.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.do_finish()?;
+        Ok(self.writer)
+    }
+
+    fn do_finish(&mut self) -> io::Result<()> {
+        if self.state == DecoderState::Accepting {
+            self.write_from_offset()?;
+            self.state = DecoderState::Finished;
+        }
+
+        if !self.frame_complete {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "stream ended in the middle of a frame"));
+        }
+
+        Ok(())
+    }
+
+    /// Return a recommendation for the size of data to write at once.
+    pub fn recommended_input_size() -> usize {
+        unsafe { zstd_sys::ZSTD_DStreamInSize() }
+    }
+
+    /// write_all, except keep track of partial writes for non-blocking IO.
+    fn write_from_offset(&mut self) -> io::Result<()> {
+        while self.offset < self.buffer.len() {
+            match self.writer.write(&self.buffer[self.offset..]) {
+                Ok(n) => self.offset += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_internal(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.state != DecoderState::Accepting {
+            panic!("write called after finish attempted");
+        }
+
+        if self.offset < self.buffer.len() {
+            // If we still had some things to write, do it first.
+            self.offset += self.writer.write(&self.buffer[self.offset..])?;
+            // Maybe next time!
+            return Err(io::Error::new(io::ErrorKind::Interrupted,
+                                      "Internal buffer full"));
+        }
+
+        // If we get to here, `self.buffer` can safely be discarded.
+
+        let mut in_buffer = zstd_sys::ZSTD_inBuffer {
+            src: buf.as_ptr() as *const c_void,
+            size: buf.len(),
+            pos: 0,
+        };
+
+        // A single `ZSTD_decompressStream` call can fill the whole output
+        // buffer while consuming no input at all (highly expansive data,
+        // e.g. long runs), or land exactly on a frame boundary with more
+        // input still to feed it. Returning `Ok(0)` in that case would make
+        // `write_all` treat it as a `WriteZero` error even though the
+        // decoder made progress, so keep calling until some input has been
+        // consumed or there's none left to give it.
+        loop {
+            let mut out_buffer = zstd_sys::ZSTD_outBuffer {
+                dst: self.buffer.as_mut_ptr() as *mut c_void,
+                size: self.buffer.capacity(),
+                pos: 0,
+            };
+
+            unsafe {
+                let code =
+                    zstd_sys::ZSTD_decompressStream(self.context.s,
+                                                    &mut out_buffer as
+                                                    *mut zstd_sys::ZSTD_outBuffer,
+                                                    &mut in_buffer as
+                                                    *mut zstd_sys::ZSTD_inBuffer);
+                self.buffer.set_len(out_buffer.pos);
+
+                let remaining = parse_code(code)?;
+                self.frame_complete = remaining == 0;
+
+                // A frame just ended: if there's more input, it must be the
+                // start of another, concatenated frame, so re-init the
+                // stream to decode it.
+                if self.frame_complete && in_buffer.pos < in_buffer.size {
+                    self.reinit_stream()?;
+                }
+            }
+
+            // `self.buffer` is about to be overwritten by the next
+            // iteration, so it must be fully drained first; within a single
+            // `write()` call there's no one left to retry a partial write
+            // for, unlike the buffering across separate `write()` calls
+            // above.
+            self.offset = 0;
+            self.write_from_offset()?;
+
+            if in_buffer.pos > 0 || in_buffer.pos == in_buffer.size {
+                break;
+            }
+        }
+
+        Ok(in_buffer.pos)
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_internal(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_from_offset()?;
+        self.writer.flush()
+    }
+}
+
+use futures::Poll;
+use tokio_io::AsyncWrite;
+
+impl<W: AsyncWrite> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.write_internal(buf) {
+                Ok(n) => return Ok(n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for Decoder<W> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.flush());
+        self.writer.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use stream::encoder::Encoder;
+    use super::Decoder;
+
+    /// A plain round-trip: whatever goes in the encoder should come back
+    /// out the other end of the decoder, unchanged.
+    #[test]
+    fn test_roundtrip() {
+        let input = "hello zstd, this is a streaming decoder"
+            .repeat(64)
+            .into_bytes();
+
+        let mut e = Encoder::new(Vec::new(), 1).unwrap();
+        e.write_all(&input).unwrap();
+        let compressed = e.finish().unwrap();
+
+        let mut d = Decoder::new(Vec::new()).unwrap();
+        d.write_all(&compressed).unwrap();
+        let decompressed = d.finish().unwrap();
+
+        assert_eq!(decompressed, input);
+    }
+
+    /// Several frames concatenated together should decode as a single
+    /// continuous stream, re-initializing the decoder between them.
+    #[test]
+    fn test_roundtrip_concatenated_frames() {
+        let first = b"first frame".to_vec();
+        let second = b"second frame, after the first one".to_vec();
+
+        let mut first_encoder = Encoder::new(Vec::new(), 1).unwrap();
+        first_encoder.write_all(&first).unwrap();
+
+        let mut second_encoder = Encoder::new(Vec::new(), 1).unwrap();
+        second_encoder.write_all(&second).unwrap();
+
+        let mut compressed = first_encoder.finish().unwrap();
+        compressed.extend(second_encoder.finish().unwrap());
+
+        let mut d = Decoder::new(Vec::new()).unwrap();
+        d.write_all(&compressed).unwrap();
+        let decompressed = d.finish().unwrap();
+
+        let mut expected = first;
+        expected.extend(second);
+        assert_eq!(decompressed, expected);
+    }
+}
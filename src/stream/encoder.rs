@@ -1,7 +1,12 @@
 use libc::c_void;
 
 use parse_code;
+use std::collections::BTreeMap;
 use std::io::{self, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use stream::frame::compress_frame;
 use zstd_sys;
 
 struct EncoderContext {
@@ -52,6 +57,142 @@ pub struct Encoder<W: Write> {
     state: EncoderState,
 }
 
+/// Configures an `Encoder` before the stream starts, in the spirit of the
+/// header options gzip/zlib encoders expose.
+///
+/// `Encoder::new`/`with_dictionary` are thin wrappers around this builder
+/// using its defaults; reach for `EncoderBuilder` directly to set a
+/// checksum, a pledged content size, or long-distance matching.
+pub struct EncoderBuilder {
+    level: i32,
+    dictionary: Vec<u8>,
+    checksum: bool,
+    content_size: Option<u64>,
+    window_log: Option<u32>,
+}
+
+impl Default for EncoderBuilder {
+    fn default() -> Self {
+        EncoderBuilder {
+            level: 0,
+            dictionary: Vec::new(),
+            checksum: false,
+            content_size: None,
+            window_log: None,
+        }
+    }
+}
+
+impl EncoderBuilder {
+    /// Creates a new builder, using the default compression level, no
+    /// checksum, no pledged content size, and no long-distance matching.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the compression level (1-21).
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Sets an existing dictionary to compress against.
+    ///
+    /// (Provides better compression ratio for small files, but requires the
+    /// dictionary to be present during decompression.)
+    pub fn dictionary(mut self, dictionary: Vec<u8>) -> Self {
+        self.dictionary = dictionary;
+        self
+    }
+
+    /// Enables the 4-byte XXH64 frame checksum, so decoders can detect
+    /// corruption.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Records the total pledged input size in the frame header.
+    ///
+    /// Useful for progress bars on the decoding side, and lets decoders
+    /// allocate the exact output buffer up front for single-shot decoding.
+    /// Writing a different number of bytes than `content_size` before
+    /// `finish()` is an error.
+    pub fn content_size(mut self, content_size: u64) -> Self {
+        self.content_size = Some(content_size);
+        self
+    }
+
+    /// Sets the window log used for long-distance matching, improving the
+    /// compression ratio on large, redundant inputs at the cost of memory.
+    pub fn window_log(mut self, window_log: u32) -> Self {
+        self.window_log = Some(window_log);
+        self
+    }
+
+    /// Builds the `Encoder`, writing compressed data to `writer`.
+    pub fn build<W: Write>(self, writer: W) -> io::Result<Encoder<W>> {
+        let context = EncoderContext::default();
+
+        // The advanced CCtx-parameter API replaces the old
+        // `ZSTD_initCStream_usingDict`: parameters are set on the context
+        // up front, and the first call to `ZSTD_compressStream` starts a
+        // new session using them.
+        parse_code(unsafe {
+            zstd_sys::ZSTD_CCtx_setParameter(context.s,
+                                             zstd_sys::ZSTD_cParameter::ZSTD_c_compressionLevel,
+                                             self.level)
+        })?;
+
+        if self.checksum {
+            parse_code(unsafe {
+                zstd_sys::ZSTD_CCtx_setParameter(context.s,
+                                                 zstd_sys::ZSTD_cParameter::ZSTD_c_checksumFlag,
+                                                 1)
+            })?;
+        }
+
+        if let Some(window_log) = self.window_log {
+            parse_code(unsafe {
+                zstd_sys::ZSTD_CCtx_setParameter(context.s,
+                                                 zstd_sys::ZSTD_cParameter::ZSTD_c_windowLog,
+                                                 window_log as i32)
+            })?;
+            parse_code(unsafe {
+                zstd_sys::ZSTD_CCtx_setParameter(context.s,
+                                                 zstd_sys::ZSTD_cParameter::ZSTD_c_enableLongDistanceMatching,
+                                                 1)
+            })?;
+        }
+
+        if let Some(content_size) = self.content_size {
+            parse_code(unsafe {
+                zstd_sys::ZSTD_CCtx_setPledgedSrcSize(context.s, content_size)
+            })?;
+        }
+
+        if !self.dictionary.is_empty() {
+            // `refPrefix` only references the buffer, which must then
+            // outlive compression; `self.dictionary` doesn't survive past
+            // this function. It also treats the buffer as a raw content
+            // prefix rather than a trained dictionary, so it would ignore
+            // the entropy tables produced by `train_from_samples`.
+            // `loadDictionary` copies the bytes into the context and
+            // auto-detects a trained dictionary, matching the semantics
+            // `ZSTD_initCStream_usingDict` used to provide (and that
+            // `Decoder::with_dictionary` expects on the other end).
+            parse_code(unsafe {
+                zstd_sys::ZSTD_CCtx_loadDictionary(context.s,
+                                                  self.dictionary.as_ptr() as
+                                                  *const c_void,
+                                                  self.dictionary.len())
+            })?;
+        }
+
+        Encoder::with_context(writer, context)
+    }
+}
+
 /// A wrapper around an `Encoder<W>` that finishes the stream on drop.
 pub struct AutoFinishEncoder<W: Write> {
     // We wrap this in an option to take it during drop.
@@ -133,17 +274,10 @@ impl<W: Write> Encoder<W> {
     /// but requires the dictionary to be present during decompression.)
     pub fn with_dictionary(writer: W, level: i32, dictionary: &[u8])
                            -> io::Result<Self> {
-        let context = EncoderContext::default();
-
-        // Initialize the stream with an existing dictionary
-        parse_code(unsafe {
-            zstd_sys::ZSTD_initCStream_usingDict(context.s,
-                                           dictionary.as_ptr() as *const c_void,
-                                           dictionary.len(),
-                                           level)
-        })?;
-
-        Encoder::with_context(writer, context)
+        EncoderBuilder::new()
+            .level(level)
+            .dictionary(dictionary.to_vec())
+            .build(writer)
     }
 
     /// Returns a wrapper around `self` that will finish the stream on drop.
@@ -227,24 +361,32 @@ impl<W: Write> Encoder<W> {
         }
 
         if self.state == EncoderState::Finished {
-            // First, closes the stream.
-            let mut buffer = zstd_sys::ZSTD_outBuffer {
-                dst: self.buffer.as_mut_ptr() as *mut c_void,
-                size: self.buffer.capacity(),
-                pos: 0,
-            };
-            let remaining = parse_code(unsafe {
-                zstd_sys::ZSTD_endStream(self.context.s,
-                                   &mut buffer as *mut zstd_sys::ZSTD_outBuffer)
-            })?;
-            unsafe {
-                self.buffer.set_len(buffer.pos);
-            }
-            if remaining != 0 {
-                // Need to flush?
-                panic!("Need to flush, but I'm lazy.");
+            // Closes the stream. A single `ZSTD_endStream` call isn't
+            // guaranteed to flush everything if `self.buffer` is too small
+            // to hold the whole footer (e.g. with a tiny
+            // `ZSTD_CStreamOutSize`, or a checksum/content-size footer), so
+            // keep calling it, draining `self.buffer` to the writer each
+            // time, until it reports nothing remaining.
+            loop {
+                let mut buffer = zstd_sys::ZSTD_outBuffer {
+                    dst: self.buffer.as_mut_ptr() as *mut c_void,
+                    size: self.buffer.capacity(),
+                    pos: 0,
+                };
+                let remaining = parse_code(unsafe {
+                    zstd_sys::ZSTD_endStream(self.context.s,
+                                       &mut buffer as *mut zstd_sys::ZSTD_outBuffer)
+                })?;
+                unsafe {
+                    self.buffer.set_len(buffer.pos);
+                }
+                self.offset = 0;
+                self.write_from_offset()?;
+
+                if remaining == 0 {
+                    break;
+                }
             }
-            self.offset = 0;
             self.state = EncoderState::StreamEnd;
         }
 
@@ -332,20 +474,31 @@ impl<W: Write> Write for Encoder<W> {
         if self.state == EncoderState::Accepting {
             self.write_from_offset()?;
 
-            let mut buffer = zstd_sys::ZSTD_outBuffer {
-                dst: self.buffer.as_mut_ptr() as *mut c_void,
-                size: self.buffer.capacity(),
-                pos: 0,
-            };
-            unsafe {
-                let code =
-                    zstd_sys::ZSTD_flushStream(self.context.s,
-                                               &mut buffer as
-                                               *mut zstd_sys::ZSTD_outBuffer);
-                self.buffer.set_len(buffer.pos);
-                let _ = parse_code(code)?;
+            // As with `do_finish`, `ZSTD_flushStream` may need several
+            // calls to fully drain zstd's internal buffers into ours when
+            // `self.buffer` is small, so loop until it reports nothing
+            // remaining instead of assuming one call is enough.
+            loop {
+                let mut buffer = zstd_sys::ZSTD_outBuffer {
+                    dst: self.buffer.as_mut_ptr() as *mut c_void,
+                    size: self.buffer.capacity(),
+                    pos: 0,
+                };
+                let remaining = unsafe {
+                    let code =
+                        zstd_sys::ZSTD_flushStream(self.context.s,
+                                                   &mut buffer as
+                                                   *mut zstd_sys::ZSTD_outBuffer);
+                    self.buffer.set_len(buffer.pos);
+                    parse_code(code)?
+                };
+                self.offset = 0;
+                self.write_from_offset()?;
+
+                if remaining == 0 {
+                    break;
+                }
             }
-            self.offset = 0;
         }
 
         self.write_from_offset()?;
@@ -375,11 +528,362 @@ impl<W: AsyncWrite> AsyncWrite for Encoder<W> {
     }
 }
 
+/// Default size, in bytes, of the blocks fed to `ParEncoder`'s worker
+/// threads.
+const DEFAULT_BLOCK_SIZE: usize = 1024 * 1024;
+
+enum ParJob {
+    Block {
+        seq: u64,
+        data: Vec<u8>,
+        level: i32,
+        dictionary: Arc<Vec<u8>>,
+    },
+}
+
+struct ParFrame {
+    seq: u64,
+    data: io::Result<Vec<u8>>,
+}
+
+/// Builder for `ParEncoder`, configuring the number of worker threads and
+/// the size of the blocks they compress.
+pub struct ParEncoderBuilder {
+    level: i32,
+    num_threads: usize,
+    block_size: usize,
+    dictionary: Vec<u8>,
+}
+
+impl Default for ParEncoderBuilder {
+    fn default() -> Self {
+        ParEncoderBuilder {
+            level: 0,
+            num_threads: ::num_cpus::get(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            dictionary: Vec::new(),
+        }
+    }
+}
+
+impl ParEncoderBuilder {
+    /// Creates a new builder, using the default compression level, one
+    /// worker thread per CPU, and a 1 MiB block size.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the compression level used for each block (1-21).
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Sets the number of worker threads used to compress blocks.
+    ///
+    /// Defaults to the number of available CPUs. At least one thread is
+    /// always used.
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = ::std::cmp::max(1, num_threads);
+        self
+    }
+
+    /// Sets the size, in bytes, of the blocks handed off to worker threads.
+    ///
+    /// Larger blocks compress better but reduce parallelism and increase
+    /// latency; smaller blocks do the opposite. Defaults to 1 MiB.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets a dictionary to use when compressing each block.
+    pub fn dictionary(mut self, dictionary: Vec<u8>) -> Self {
+        self.dictionary = dictionary;
+        self
+    }
+
+    /// Builds a `ParEncoder` writing to `writer`.
+    pub fn build<W: Write + Send + 'static>(self, writer: W)
+                                            -> io::Result<ParEncoder<W>> {
+        ParEncoder::with_builder(writer, self)
+    }
+}
+
+/// A multi-threaded encoder splitting its input into independent blocks
+/// compressed in parallel, in the spirit of gzp's `ParCompress`.
+///
+/// Input is accumulated into fixed-size blocks; each completed block is
+/// hand off to a worker thread, which runs a one-shot compression producing
+/// a self-contained zstd frame. A dedicated writer thread then emits the
+/// resulting frames to the wrapped `W`, strictly in submission order.
+///
+/// Since zstd transparently decodes a concatenation of independent frames,
+/// the result is a standard `.zst` stream: readable by `Decoder`, or by the
+/// `zstd` CLI, even though several threads produced it.
+///
+/// The worker pool is fed through a bounded channel, so `write()` blocks
+/// once every worker is busy, applying backpressure instead of buffering
+/// unboundedly.
+///
+/// Don't forget to call `finish()` before dropping it!
+pub struct ParEncoder<W: Write> {
+    block_size: usize,
+    pending: Vec<u8>,
+    next_seq: u64,
+    level: i32,
+    dictionary: Arc<Vec<u8>>,
+
+    job_tx: Option<SyncSender<ParJob>>,
+    workers: Vec<thread::JoinHandle<()>>,
+    writer: Option<thread::JoinHandle<io::Result<W>>>,
+}
+
+/// A wrapper around a `ParEncoder<W>` that finishes the stream on drop.
+///
+/// Without this, a `ParEncoder` dropped without calling `finish()` would
+/// silently discard its final partial block and detach its worker/writer
+/// threads instead of flushing and joining them.
+pub struct AutoFinishParEncoder<W: Write + Send + 'static> {
+    // We wrap this in an option to take it during drop.
+    encoder: Option<ParEncoder<W>>,
+    // TODO: make this a FnOnce once it works in a Box
+    on_finish: Option<Box<FnMut(io::Result<W>)>>,
+}
+
+impl<W: Write + Send + 'static> AutoFinishParEncoder<W> {
+    fn new<F: 'static + FnMut(io::Result<W>)>(encoder: ParEncoder<W>,
+                                              on_finish: F)
+                                              -> Self {
+        AutoFinishParEncoder {
+            encoder: Some(encoder),
+            on_finish: Some(Box::new(on_finish)),
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> Drop for AutoFinishParEncoder<W> {
+    fn drop(&mut self) {
+        let result = self.encoder
+            .take()
+            .unwrap()
+            .finish();
+        if let Some(mut on_finish) = self.on_finish.take() {
+            on_finish(result);
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> Write for AutoFinishParEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder
+            .as_mut()
+            .unwrap()
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder
+            .as_mut()
+            .unwrap()
+            .flush()
+    }
+}
+
+impl<W: Write + Send + 'static> ParEncoder<W> {
+    /// Creates a new parallel encoder, using one worker thread per CPU and
+    /// a 1 MiB block size.
+    ///
+    /// `level`: compression level (1-21), applied to every block.
+    pub fn new(writer: W, level: i32) -> io::Result<Self> {
+        ParEncoderBuilder::new().level(level).build(writer)
+    }
+
+    /// Returns a wrapper around `self` that will finish the stream on drop.
+    ///
+    /// # Panic
+    ///
+    /// Panics on drop if an error happens when finishing the stream.
+    pub fn auto_finish(self) -> AutoFinishParEncoder<W> {
+        self.on_finish(|result| { result.unwrap(); })
+    }
+
+    /// Returns an encoder that will finish the stream on drop.
+    ///
+    /// Calls the given callback with the result from `finish()`.
+    pub fn on_finish<F: 'static + FnMut(io::Result<W>)>
+        (self, f: F)
+         -> AutoFinishParEncoder<W> {
+        AutoFinishParEncoder::new(self, f)
+    }
+
+    fn with_builder(writer: W, builder: ParEncoderBuilder)
+                    -> io::Result<Self> {
+        let num_threads = ::std::cmp::max(1, builder.num_threads);
+        let dictionary = Arc::new(builder.dictionary);
+
+        // A zero-capacity (rendezvous) job channel is what makes `write()`
+        // block once every worker is busy: a send only completes when a
+        // worker is free to take the block.
+        let (job_tx, job_rx) = sync_channel::<ParJob>(0);
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        let (frame_tx, frame_rx) = sync_channel::<ParFrame>(num_threads * 2);
+
+        let mut workers = Vec::with_capacity(num_threads);
+        for _ in 0..num_threads {
+            let job_rx: Arc<Mutex<Receiver<ParJob>>> = Arc::clone(&job_rx);
+            let frame_tx = frame_tx.clone();
+            workers.push(thread::spawn(move || {
+                loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let ParJob::Block { seq, data, level, dictionary } =
+                        match job {
+                            Ok(job) => job,
+                            Err(_) => break,
+                        };
+                    let frame = compress_frame(&data, level, &dictionary);
+                    if frame_tx
+                           .send(ParFrame {
+                                     seq: seq,
+                                     data: frame,
+                                 })
+                           .is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+        // Workers each hold their own clone; the writer thread should only
+        // see the channel close once every worker is done with it.
+        drop(frame_tx);
+
+        let writer_thread = thread::spawn(move || -> io::Result<W> {
+            let mut writer = writer;
+            let mut next_seq = 0u64;
+            let mut pending = BTreeMap::new();
+            let mut first_err = None;
+
+            while let Ok(frame) = frame_rx.recv() {
+                if first_err.is_some() {
+                    continue;
+                }
+                match frame.data {
+                    Ok(data) => {
+                        pending.insert(frame.seq, data);
+                        while let Some(data) = pending.remove(&next_seq) {
+                            if let Err(e) = writer.write_all(&data) {
+                                first_err = Some(e);
+                                break;
+                            }
+                            next_seq += 1;
+                        }
+                    }
+                    Err(e) => first_err = Some(e),
+                }
+            }
+
+            if let Some(e) = first_err {
+                return Err(e);
+            }
+            writer.flush()?;
+            Ok(writer)
+        });
+
+        Ok(ParEncoder {
+               block_size: builder.block_size,
+               pending: Vec::with_capacity(builder.block_size),
+               next_seq: 0,
+               level: builder.level,
+               dictionary: dictionary,
+               job_tx: Some(job_tx),
+               workers: workers,
+               writer: Some(writer_thread),
+           })
+    }
+
+    /// Hands the current pending block off to a worker thread, blocking
+    /// until a worker is free to accept it.
+    fn submit_block(&mut self) -> io::Result<()> {
+        let data = ::std::mem::replace(&mut self.pending,
+                                       Vec::with_capacity(self.block_size));
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.job_tx
+            .as_ref()
+            .unwrap()
+            .send(ParJob::Block {
+                      seq: seq,
+                      data: data,
+                      level: self.level,
+                      dictionary: Arc::clone(&self.dictionary),
+                  })
+            .map_err(|_| {
+                         io::Error::new(io::ErrorKind::Other,
+                                        "a worker thread panicked")
+                     })
+    }
+
+    /// Finishes the stream: flushes the final partial block, waits for
+    /// every worker and the writer thread, and returns the inner writer.
+    ///
+    /// This propagates the first error hit by any worker thread, or by the
+    /// writer thread while emitting frames to `W`.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.pending.is_empty() {
+            self.submit_block()?;
+        }
+
+        // Dropping every sender makes the workers' blocking `recv()` return
+        // an error, which is how they learn it's time to stop.
+        self.job_tx.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+
+        self.writer
+            .take()
+            .unwrap()
+            .join()
+            .unwrap_or_else(|_| {
+                                Err(io::Error::new(io::ErrorKind::Other,
+                                                   "the writer thread panicked"))
+                            })
+    }
+}
+
+impl<W: Write + Send + 'static> Write for ParEncoder<W> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let written = buf.len();
+        while !buf.is_empty() {
+            let space = self.block_size - self.pending.len();
+            let n = ::std::cmp::min(space, buf.len());
+            self.pending.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+
+            if self.pending.len() >= self.block_size {
+                self.submit_block()?;
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Blocks are only ever submitted whole, so there's nothing that can
+        // be flushed to `W` without calling `finish()` and ending the
+        // stream; this matches `finish()` being the only way to guarantee
+        // pending data has reached the writer.
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use stream::decode_all;
+    use stream::decoder::Decoder;
     use stream::tests::WritePartial;
-    use super::Encoder;
+    use super::{Encoder, EncoderBuilder};
 
     /// Test that flush after a partial write works successfully without
     /// corrupting the frame. This test is in this module because it checks
@@ -409,6 +913,71 @@ mod tests {
         assert_eq!(&decode_all(&buf[..]).unwrap(), &input);
     }
 
+    /// A dictionary must round-trip through `Encoder::with_dictionary` and
+    /// `Decoder::with_dictionary`: this is what would have caught the
+    /// `ZSTD_CCtx_refPrefix` use-after-free (the dictionary buffer is
+    /// dropped as soon as `with_dictionary` returns) and its mismatched
+    /// semantics versus a trained dictionary.
+    #[test]
+    fn test_dictionary_roundtrip() {
+        use std::io::Write;
+        use dict::train_from_samples;
+
+        // ZDICT_trainFromBuffer needs the training corpus to substantially
+        // exceed the target dictionary size, so use thousands of varied
+        // samples (a few hundred KiB) to train a dictionary an order of
+        // magnitude smaller.
+        let samples: Vec<Vec<u8>> =
+            (0..8192u32)
+                .map(|i| {
+                         format!("sample number {}, with some shared \
+                                  structure and a varying tail {:x}",
+                                 i,
+                                 i.wrapping_mul(2654435761u32))
+                                 .into_bytes()
+                     })
+                .collect();
+        let sample_refs: Vec<&[u8]> =
+            samples.iter().map(|s| s.as_slice()).collect();
+        let dictionary = train_from_samples(&sample_refs, 4 * 1024).unwrap();
+
+        let input = b"sample number 99999, with some shared structure and \
+                      a varying tail deadbeef"
+            .to_vec();
+
+        let mut z =
+            Encoder::with_dictionary(Vec::new(), 1, &dictionary).unwrap();
+        z.write_all(&input).unwrap();
+        let compressed = z.finish().unwrap();
+
+        let mut d =
+            Decoder::with_dictionary(Vec::new(), &dictionary).unwrap();
+        d.write_all(&compressed).unwrap();
+        let decompressed = d.finish().unwrap();
+
+        assert_eq!(decompressed, input);
+    }
+
+    /// Checksum and pledged content size shouldn't change what comes out
+    /// the other end.
+    #[test]
+    fn test_builder_roundtrip() {
+        use std::io::Write;
+
+        let input = "some text to compress".repeat(16).into_bytes();
+
+        let mut z = EncoderBuilder::new()
+            .level(3)
+            .checksum(true)
+            .content_size(input.len() as u64)
+            .build(Vec::new())
+            .unwrap();
+        z.write_all(&input).unwrap();
+        let compressed = z.finish().unwrap();
+
+        assert_eq!(&decode_all(&compressed[..]).unwrap(), &input);
+    }
+
     fn setup_partial_write() -> (Vec<u8>, Encoder<WritePartial>) {
         use std::io::Write;
 
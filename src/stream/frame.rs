@@ -0,0 +1,41 @@
+use libc::c_void;
+
+use parse_code;
+use std::io;
+use zstd_sys;
+
+/// Compresses `data` as a single, self-contained frame using the one-shot
+/// `ZSTD_compress_usingDict` API, rather than the streaming `ZSTD_CStream`
+/// machinery `Encoder` uses.
+///
+/// Shared by the two encoders that each produce several independent
+/// frames outside of a single streaming session: `ParEncoder`'s worker
+/// threads (one frame per block, run in parallel) and `SeekableEncoder`
+/// (one frame per seek point). Pass an empty `dictionary` to compress
+/// without one.
+pub fn compress_frame(data: &[u8], level: i32, dictionary: &[u8])
+                      -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(unsafe {
+        zstd_sys::ZSTD_compressBound(data.len())
+    });
+
+    let cctx = unsafe { zstd_sys::ZSTD_createCCtx() };
+    let written = parse_code(unsafe {
+        let code = zstd_sys::ZSTD_compress_usingDict(cctx,
+                                             buffer.as_mut_ptr() as
+                                             *mut c_void,
+                                             buffer.capacity(),
+                                             data.as_ptr() as *const c_void,
+                                             data.len(),
+                                             dictionary.as_ptr() as
+                                             *const c_void,
+                                             dictionary.len(),
+                                             level);
+        zstd_sys::ZSTD_freeCCtx(cctx);
+        code
+    })?;
+    unsafe {
+        buffer.set_len(written);
+    }
+    Ok(buffer)
+}
@@ -0,0 +1,226 @@
+use std::io::{self, Write};
+
+use stream::frame::compress_frame;
+
+/// Magic number of a zstd skippable frame, used to hold the seek table.
+const SKIPPABLE_FRAME_MAGICNUMBER: u32 = 0x184D2A5E;
+/// Magic number ending the seek table itself, identifying it among
+/// skippable frames.
+const SEEKABLE_MAGICNUMBER: u32 = 0x8F92EAB1;
+
+/// Default size, in bytes, of the uncompressed data held by each frame.
+const DEFAULT_FRAME_SIZE: u32 = 2 * 1024 * 1024;
+
+struct SeekTableEntry {
+    compressed_size: u32,
+    decompressed_size: u32,
+}
+
+/// Builder for `SeekableEncoder`, configuring the compression level and the
+/// size of each seekable frame.
+pub struct SeekableEncoderBuilder {
+    level: i32,
+    frame_size: u32,
+}
+
+impl Default for SeekableEncoderBuilder {
+    fn default() -> Self {
+        SeekableEncoderBuilder {
+            level: 0,
+            frame_size: DEFAULT_FRAME_SIZE,
+        }
+    }
+}
+
+impl SeekableEncoderBuilder {
+    /// Creates a new builder, using the default compression level and a
+    /// 2 MiB frame size.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the compression level (1-21), applied to every frame.
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Sets the number of uncompressed bytes held by each frame.
+    ///
+    /// A new frame is started every `frame_size` bytes written; smaller
+    /// frames allow seeking to finer-grained offsets at the cost of
+    /// compression ratio.
+    pub fn frame_size(mut self, frame_size: u32) -> Self {
+        self.frame_size = frame_size;
+        self
+    }
+
+    /// Builds a `SeekableEncoder` writing to `writer`.
+    ///
+    /// Errors if `frame_size` is 0: `write()` accumulates bytes into the
+    /// current frame until it reaches `frame_size`, so a 0-sized frame
+    /// would never be considered full and would spin forever.
+    pub fn build<W: Write>(self, writer: W) -> io::Result<SeekableEncoder<W>> {
+        if self.frame_size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "frame_size must be non-zero"));
+        }
+
+        Ok(SeekableEncoder {
+               writer: writer,
+               level: self.level,
+               frame_size: self.frame_size,
+               pending: Vec::with_capacity(self.frame_size as usize),
+               entries: Vec::new(),
+           })
+    }
+}
+
+/// An encoder writing zstd's seekable format: a sequence of independently
+/// decodable frames, each holding at most `frame_size` uncompressed bytes,
+/// followed by a seek-table skippable frame recording the size of each one.
+///
+/// This lets consumers jump to and decompress an arbitrary offset without
+/// reading the whole stream first (for instance, to demand-page a large
+/// compressed blob), while the file as a whole remains a standard,
+/// concatenation-of-frames `.zst` stream.
+///
+/// Don't forget to call `finish()` before dropping it!
+pub struct SeekableEncoder<W: Write> {
+    writer: W,
+    level: i32,
+    frame_size: u32,
+    // Uncompressed bytes accumulated for the current frame.
+    pending: Vec<u8>,
+    entries: Vec<SeekTableEntry>,
+}
+
+impl<W: Write> SeekableEncoder<W> {
+    /// Creates a new seekable encoder, using a 2 MiB frame size.
+    ///
+    /// `level`: compression level (1-21), applied to every frame.
+    pub fn new(writer: W, level: i32) -> io::Result<Self> {
+        SeekableEncoderBuilder::new().level(level).build(writer)
+    }
+
+    /// Compresses and writes out the current pending frame, if any, and
+    /// records its sizes in the seek table.
+    fn flush_frame(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let decompressed_size = self.pending.len();
+        let frame = compress_frame(&self.pending, self.level, &[])?;
+        self.writer.write_all(&frame)?;
+
+        self.entries
+            .push(SeekTableEntry {
+                      compressed_size: frame.len() as u32,
+                      decompressed_size: decompressed_size as u32,
+                  });
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    /// Appends the seek-table skippable frame summarizing every data frame
+    /// written so far.
+    fn write_seek_table(&mut self) -> io::Result<()> {
+        let mut body = Vec::with_capacity(8 * self.entries.len() + 9);
+        for entry in &self.entries {
+            body.extend_from_slice(&entry.compressed_size.to_le_bytes());
+            body.extend_from_slice(&entry.decompressed_size.to_le_bytes());
+        }
+
+        // Footer: number of frames, a descriptor byte (no per-frame
+        // checksum, no user-defined field), and the seek table's own magic
+        // number.
+        body.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        body.push(0);
+        body.extend_from_slice(&SEEKABLE_MAGICNUMBER.to_le_bytes());
+
+        self.writer
+            .write_all(&SKIPPABLE_FRAME_MAGICNUMBER.to_le_bytes())?;
+        self.writer.write_all(&(body.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&body)?;
+
+        Ok(())
+    }
+
+    /// Finishes the stream: flushes the last partial frame, appends the
+    /// seek table, and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_frame()?;
+        self.write_seek_table()?;
+        Ok(self.writer)
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+}
+
+impl<W: Write> Write for SeekableEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = buf.len();
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let space = self.frame_size as usize - self.pending.len();
+            let n = ::std::cmp::min(space, buf.len());
+            self.pending.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+
+            if self.pending.len() >= self.frame_size as usize {
+                self.flush_frame()?;
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Frames are only ever emitted whole (so they stay independently
+        // decodable), so there's nothing to flush to `W` without ending
+        // the current frame early; call `finish()` to guarantee pending
+        // data has reached the writer.
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use stream::decode_all;
+    use super::{SeekableEncoder, SeekableEncoderBuilder};
+
+    /// The data frames plus the trailing seek-table skippable frame should
+    /// still decode as a single standard `.zst` stream: decoders skip
+    /// skippable frames, so the data comes back out unchanged.
+    #[test]
+    fn test_roundtrip() {
+        let input = "some data, spanning several small frames"
+            .repeat(1024)
+            .into_bytes();
+
+        let mut z = SeekableEncoderBuilder::new()
+            .level(1)
+            .frame_size(1024)
+            .build(Vec::new())
+            .unwrap();
+        z.write_all(&input).unwrap();
+        let compressed = z.finish().unwrap();
+
+        assert_eq!(&decode_all(&compressed[..]).unwrap(), &input);
+    }
+
+    /// A 0-byte frame size can never be considered full, so it must be
+    /// rejected up front instead of making `write()` spin forever.
+    #[test]
+    fn test_zero_frame_size_rejected() {
+        let result = SeekableEncoderBuilder::new()
+            .frame_size(0)
+            .build(Vec::new());
+        assert!(result.is_err());
+    }
+}